@@ -0,0 +1,45 @@
+//! Native BlueZ adapter layer.
+//!
+//! Replaces shelling out to `btmgmt` with direct calls to the `org.bluez`
+//! `LEAdvertisingManager1` interface via the [`bluer`] crate. Each registered
+//! advertisement is represented by an [`AdvertisementHandle`]; dropping the
+//! handle unregisters the advertisement with BlueZ, so distinct advertisements
+//! can run simultaneously without colliding on a shared instance id.
+
+use std::collections::BTreeMap;
+
+use bluer::adv::{Advertisement, AdvertisementHandle, Type};
+
+/// Handle to the local Bluetooth adapter used to register advertisements.
+pub struct BleController {
+    adapter: bluer::Adapter,
+}
+
+impl BleController {
+    /// Connect to the system BlueZ daemon and power on the default adapter.
+    pub async fn new() -> bluer::Result<Self> {
+        let session = bluer::Session::new().await?;
+        let adapter = session.default_adapter().await?;
+        adapter.set_powered(true).await?;
+        Ok(Self { adapter })
+    }
+
+    /// Register a single advertisement carrying `advertising_data` as raw AD
+    /// structures, keyed by AD type. The structures are sent ordered by type,
+    /// not in the order of the original `btmgmt add-adv -d <data>` payload.
+    ///
+    /// The returned handle keeps the advertisement live; dropping it
+    /// unregisters the advertisement.
+    pub async fn advertise(
+        &self,
+        advertising_data: BTreeMap<u8, Vec<u8>>,
+    ) -> bluer::Result<AdvertisementHandle> {
+        let adv = Advertisement {
+            advertisement_type: Type::Broadcast,
+            advertising_data,
+            discoverable: Some(true),
+            ..Default::default()
+        };
+        self.adapter.advertise(adv).await
+    }
+}
@@ -1,13 +1,110 @@
+mod ble;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use axum::{
-    extract::Request,
+    extract::{Request, State},
     http::{header::AUTHORIZATION, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Response,
+    },
     routing::{get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
 use clap::Parser;
-use serde::Deserialize;
+use futures_util::{Stream, StreamExt};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Notify, RwLock};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::BroadcastStream;
+use tower_service::Service;
+use uuid::Uuid;
+
+/// A lifecycle event published as an advertisement starts, fails, or is torn
+/// down, fanned out to `/events` subscribers.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AdEvent {
+    AdvertisingStarted { job_id: Uuid },
+    AdvertisingFailed { job_id: Uuid, error: String },
+    AdvertisingStopped { job_id: Uuid },
+}
+
+/// A capability a token is allowed to exercise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Scope {
+    Broadcast,
+    Udp,
+}
+
+/// A token minted by `/generate_token`, with its granted scopes and optional
+/// expiry.
+#[derive(Debug, Clone)]
+struct TokenInfo {
+    scopes: HashSet<Scope>,
+    expires_at: Option<Instant>,
+}
+
+impl TokenInfo {
+    fn is_expired(&self) -> bool {
+        self.expires_at.map_or(false, |at| Instant::now() >= at)
+    }
+}
+
+/// The scopes granted to the current request, stashed in request extensions by
+/// [`auth_middleware`] so handlers can assert the scope they need.
+#[derive(Debug, Clone)]
+struct AuthContext {
+    scopes: HashSet<Scope>,
+    is_master: bool,
+}
+
+impl AuthContext {
+    /// Return `Ok(())` when the request holds `scope`, otherwise `403`.
+    fn require(&self, scope: Scope) -> Result<(), StatusCode> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+/// A running advertisement, tracked so it can be listed and cancelled before
+/// its duration elapses.
+struct Job {
+    /// Advertising payload, echoed back when listing jobs.
+    data: String,
+    /// When the advertisement started, used to compute the remaining time.
+    started: Instant,
+    /// Total requested lifetime of the advertisement.
+    duration: Duration,
+    /// Fired to cancel the advertisement before its duration elapses.
+    cancel: Arc<Notify>,
+}
+
+/// Shared application state, cloned into every handler.
+#[derive(Clone)]
+struct AppState {
+    /// The master token read from `BEARER_TOKEN`; always carries all scopes.
+    master_token: String,
+    /// Server-issued tokens keyed by their opaque string.
+    tokens: Arc<RwLock<HashMap<String, TokenInfo>>>,
+    /// Active advertisement jobs keyed by their job id.
+    jobs: Arc<RwLock<HashMap<Uuid, Job>>>,
+    /// Fan-out channel for advertisement lifecycle events.
+    events: broadcast::Sender<AdEvent>,
+    /// Native BlueZ adapter used to register advertisements.
+    ble: Arc<ble::BleController>,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -19,32 +116,93 @@ struct Args {
     /// Port to listen on
     #[arg(short, long, default_value_t = 15)]
     port: u16,
-}
 
-/// Authentication middleware that checks for a valid bearer token
-async fn auth_middleware(request: Request, next: Next) -> Result<Response, StatusCode> {
-    // Get the expected token from environment variable
-    let expected_token =
-        std::env::var("BEARER_TOKEN").map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    /// Path to the PEM-encoded TLS certificate chain. When set together with
+    /// `--tls-key`, the listener serves HTTPS instead of plain HTTP.
+    #[arg(long)]
+    tls_cert: Option<String>,
+
+    /// Path to the PEM-encoded TLS private key matching `--tls-cert`.
+    #[arg(long)]
+    tls_key: Option<String>,
+}
 
+/// Authentication middleware that resolves a bearer token to its granted
+/// scopes and stashes them in request extensions.
+///
+/// The master token carries all scopes; any other token is looked up in the
+/// shared registry and rejected if unknown or expired.
+async fn auth_middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
     // Extract the Authorization header
     let headers = request.headers();
     let auth_header = headers
         .get(AUTHORIZATION)
         .and_then(|header| header.to_str().ok());
 
-    match auth_header {
-        Some(auth) if auth.starts_with("Bearer ") => {
-            let token = &auth[7..]; // Remove "Bearer " prefix
-            if token == expected_token {
-                // Token is valid, proceed to the next middleware/handler
-                Ok(next.run(request).await)
-            } else {
-                Err(StatusCode::UNAUTHORIZED)
-            }
+    let token = match auth_header {
+        Some(auth) if auth.starts_with("Bearer ") => &auth[7..], // Remove "Bearer " prefix
+        _ => return Err(StatusCode::UNAUTHORIZED),
+    };
+
+    let context = if token == state.master_token {
+        AuthContext {
+            scopes: HashSet::from([Scope::Broadcast, Scope::Udp]),
+            is_master: true,
         }
-        _ => Err(StatusCode::UNAUTHORIZED),
+    } else {
+        let tokens = state.tokens.read().await;
+        let info = tokens.get(token).ok_or(StatusCode::UNAUTHORIZED)?;
+        if info.is_expired() {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        AuthContext {
+            scopes: info.scopes.clone(),
+            is_master: false,
+        }
+    };
+
+    request.extensions_mut().insert(context);
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize)]
+struct GenerateTokenRequest {
+    /// Scopes the minted token should carry.
+    scopes: Vec<Scope>,
+    /// Optional lifetime in seconds; the token never expires when omitted.
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct GenerateTokenResponse {
+    token: String,
+}
+
+/// Mint a new scoped token. Only reachable with the master token.
+async fn generate_token_handler(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(payload): Json<GenerateTokenRequest>,
+) -> Result<Json<GenerateTokenResponse>, StatusCode> {
+    if !auth.is_master {
+        return Err(StatusCode::FORBIDDEN);
     }
+
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+    let info = TokenInfo {
+        scopes: payload.scopes.into_iter().collect(),
+        expires_at: payload
+            .ttl_seconds
+            .map(|ttl| Instant::now() + Duration::from_secs(ttl)),
+    };
+
+    state.tokens.write().await.insert(token.clone(), info);
+
+    Ok(Json(GenerateTokenResponse { token }))
 }
 
 #[derive(Deserialize)]
@@ -53,68 +211,172 @@ struct BroadcastRequest {
     duration: u64,
 }
 
-async fn broadcast_handler(Json(payload): Json<BroadcastRequest>) -> StatusCode {
+#[derive(Serialize)]
+struct BroadcastResponse {
+    job_id: Uuid,
+}
+
+/// Split a raw advertising-data blob into its AD structures, keyed by AD type.
+///
+/// The blob is a sequence of `length`-prefixed structures (`len`, `type`, then
+/// `len - 1` value bytes), exactly as carried in the legacy `btmgmt -d`
+/// payload. Returns `None` if a structure runs past the end of the buffer.
+fn parse_ad_structures(bytes: &[u8]) -> Option<BTreeMap<u8, Vec<u8>>> {
+    let mut data = BTreeMap::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let len = bytes[i] as usize;
+        if len == 0 {
+            break;
+        }
+        let end = i + 1 + len;
+        if end > bytes.len() {
+            return None;
+        }
+        data.insert(bytes[i + 1], bytes[i + 2..end].to_vec());
+        i = end;
+    }
+    Some(data)
+}
+
+async fn broadcast_handler(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    Json(payload): Json<BroadcastRequest>,
+) -> Result<(StatusCode, Json<BroadcastResponse>), StatusCode> {
+    auth.require(Scope::Broadcast)?;
+
+    // Validate the payload as hex bytes up front rather than splicing it into a
+    // shell string, then split it into the AD structures BlueZ expects. The
+    // structures are keyed by AD type, so they are sent ordered by type and a
+    // repeated type keeps only its last value.
+    let bytes = hex::decode(&payload.data).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let advertising_data = parse_ad_structures(&bytes).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let job_id = Uuid::new_v4();
+    let cancel = Arc::new(Notify::new());
+    let duration = Duration::from_secs(payload.duration);
+
+    // Register the advertisement synchronously so real BlueZ errors surface
+    // back to the caller.
+    let adv_handle = match state.ble.advertise(advertising_data).await {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Failed to register advertisement: {}", e);
+            let _ = state.events.send(AdEvent::AdvertisingFailed {
+                job_id,
+                error: e.to_string(),
+            });
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    // Register the job. BlueZ tracks the underlying advertisement object via the
+    // `AdvertisementHandle` held by the background task; the job id is the
+    // caller's handle for listing and cancellation.
+    state.jobs.write().await.insert(
+        job_id,
+        Job {
+            data: payload.data.clone(),
+            started: Instant::now(),
+            duration,
+            cancel: cancel.clone(),
+        },
+    );
+
+    let _ = state.events.send(AdEvent::AdvertisingStarted { job_id });
+
+    let task_state = state.clone();
     tokio::spawn(async move {
-        let add_adv_cmd = format!("btmgmt add-adv -d {} 1", payload.data);
-        let rm_adv_cmd = "btmgmt rm-adv 1".to_string();
-
-        // Execute add-adv command
-        let add_adv_output = tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg(&add_adv_cmd)
-            .output()
-            .await;
-
-        match add_adv_output {
-            Ok(output) => {
-                if !output.status.success() {
-                    eprintln!(
-                        "Failed to add advertisement: {}",
-                        String::from_utf8_lossy(&output.stderr)
-                    );
-                } else {
-                    println!(
-                        "Successfully added advertisement: {}",
-                        String::from_utf8_lossy(&output.stdout)
-                    );
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to execute add-adv command: {}", e);
+        // Hold the advertisement registered for its lifetime; dropping the
+        // handle unregisters it with BlueZ.
+        let adv_handle = adv_handle;
+
+        // Wait for the requested duration, or until the job is cancelled early.
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = cancel.notified() => {
+                println!("Advertisement {} cancelled early", job_id);
             }
         }
 
-        // Wait for specified duration
-        tokio::time::sleep(tokio::time::Duration::from_secs(payload.duration)).await;
-
-        // Execute rm-adv command
-        let rm_adv_output = tokio::process::Command::new("sh")
-            .arg("-c")
-            .arg(&rm_adv_cmd)
-            .output()
-            .await;
-
-        match rm_adv_output {
-            Ok(output) => {
-                if !output.status.success() {
-                    eprintln!(
-                        "Failed to remove advertisement: {}",
-                        String::from_utf8_lossy(&output.stderr)
-                    );
-                } else {
-                    println!(
-                        "Successfully removed advertisement: {}",
-                        String::from_utf8_lossy(&output.stdout)
-                    );
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to execute rm-adv command: {}", e);
+        drop(adv_handle);
+        let _ = task_state
+            .events
+            .send(AdEvent::AdvertisingStopped { job_id });
+
+        // Remove ourselves from the registry once torn down.
+        task_state.jobs.write().await.remove(&job_id);
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(BroadcastResponse { job_id })))
+}
+
+#[derive(Serialize)]
+struct JobSummary {
+    id: Uuid,
+    data: String,
+    remaining_secs: u64,
+}
+
+/// List the advertisements currently being broadcast.
+async fn list_broadcasts_handler(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> Result<Json<Vec<JobSummary>>, StatusCode> {
+    auth.require(Scope::Broadcast)?;
+
+    let jobs = state.jobs.read().await;
+    let summaries = jobs
+        .iter()
+        .map(|(id, job)| {
+            let remaining = job.duration.saturating_sub(job.started.elapsed());
+            JobSummary {
+                id: *id,
+                data: job.data.clone(),
+                remaining_secs: remaining.as_secs(),
             }
+        })
+        .collect();
+
+    Ok(Json(summaries))
+}
+
+/// Cancel an advertisement before its duration elapses.
+async fn cancel_broadcast_handler(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    auth.require(Scope::Broadcast)?;
+
+    let jobs = state.jobs.read().await;
+    match jobs.get(&id) {
+        // Wake the sleeping task; it drops its `AdvertisementHandle` (which
+        // unregisters the advertisement with BlueZ) and removes itself.
+        Some(job) => {
+            job.cancel.notify_one();
+            Ok(StatusCode::ACCEPTED)
         }
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Stream advertisement lifecycle events to the client as Server-Sent Events.
+async fn events_handler(
+    State(state): State<AppState>,
+    Extension(auth): Extension<AuthContext>,
+) -> Result<Sse<impl Stream<Item = Result<Event, std::convert::Infallible>>>, StatusCode> {
+    auth.require(Scope::Broadcast)?;
+
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(|msg| async move {
+        // Drop lagged/errored frames and anything that fails to serialise.
+        let event = msg.ok()?;
+        let sse = Event::default().json_data(event).ok()?;
+        Some(Ok(sse))
     });
 
-    StatusCode::ACCEPTED
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
 }
 
 #[derive(Deserialize)]
@@ -124,7 +386,14 @@ struct UdpRequest {
     data: String,
 }
 
-async fn udp_handler(Json(payload): Json<UdpRequest>) -> StatusCode {
+async fn udp_handler(
+    Extension(auth): Extension<AuthContext>,
+    Json(payload): Json<UdpRequest>,
+) -> StatusCode {
+    if let Err(status) = auth.require(Scope::Udp) {
+        return status;
+    }
+
     // Create a UDP socket
     let socket = match tokio::net::UdpSocket::bind("0.0.0.0:0").await {
         Ok(s) => s,
@@ -155,6 +424,70 @@ async fn udp_handler(Json(payload): Json<UdpRequest>) -> StatusCode {
     }
 }
 
+/// Build a `rustls::ServerConfig` from a PEM certificate chain and private key.
+///
+/// No client authentication is configured; the chain is served together with a
+/// single private key.
+fn load_tls_config(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<rustls::ServerConfig, Box<dyn std::error::Error>> {
+    let cert_chain = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        cert_path,
+    )?))
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(
+        key_path,
+    )?))?
+    .ok_or("no private key found in key file")?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)?;
+
+    Ok(config)
+}
+
+/// Accept TLS connections from `listener` and serve them with the axum app.
+async fn serve_tls(listener: tokio::net::TcpListener, app: Router, config: rustls::ServerConfig) {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    loop {
+        let (stream, _addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let tower_service = app.clone();
+
+        tokio::spawn(async move {
+            let tls_stream = match acceptor.accept(stream).await {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("TLS handshake failed: {}", e);
+                    return;
+                }
+            };
+
+            let hyper_service = hyper::service::service_fn(move |request: Request<_>| {
+                tower_service.clone().call(request)
+            });
+
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(tls_stream), hyper_service)
+                .await
+            {
+                eprintln!("Failed to serve connection: {}", e);
+            }
+        });
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -166,15 +499,35 @@ async fn main() {
     println!("Using bearer token authentication");
     println!("Expected token: {}", bearer_token);
 
+    let ble = ble::BleController::new()
+        .await
+        .expect("failed to connect to the BlueZ adapter");
+
+    let (events, _) = broadcast::channel(100);
+    let state = AppState {
+        master_token: bearer_token,
+        tokens: Arc::new(RwLock::new(HashMap::new())),
+        jobs: Arc::new(RwLock::new(HashMap::new())),
+        events,
+        ble: Arc::new(ble),
+    };
+
     // build our application with routes
     let app = Router::new()
         .route(
             "/",
             get(|| async { "BLE Broadcast REST API - Use /broadcast or /udp endpoints" }),
         )
-        .route("/broadcast", post(broadcast_handler))
+        .route("/broadcast", post(broadcast_handler).get(list_broadcasts_handler))
+        .route("/broadcast/{id}", axum::routing::delete(cancel_broadcast_handler))
         .route("/udp", post(udp_handler))
-        .layer(axum::middleware::from_fn(auth_middleware));
+        .route("/events", get(events_handler))
+        .route("/generate_token", post(generate_token_handler))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ))
+        .with_state(state);
 
     println!("Listening on {}:{}", args.address, args.port);
 
@@ -182,5 +535,17 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind(format!("{}:{}", args.address, args.port))
         .await
         .unwrap();
-    axum::serve(listener, app).await.unwrap();
+
+    // Serve over TLS when both flags are supplied, otherwise fall back to plain
+    // HTTP so existing deployments keep working.
+    match (args.tls_cert.as_deref(), args.tls_key.as_deref()) {
+        (Some(cert), Some(key)) => {
+            let config = load_tls_config(cert, key).expect("failed to load TLS configuration");
+            println!("TLS enabled");
+            serve_tls(listener, app, config).await;
+        }
+        _ => {
+            axum::serve(listener, app).await.unwrap();
+        }
+    }
 }